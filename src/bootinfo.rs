@@ -14,25 +14,117 @@ use x86_64::{
     structures::paging::{
         PhysFrameRange,
         PhysFrame,
+        FrameAllocator,
+        Size4KiB,
     },
 };
 
+/// Identifies a valid `BootInfo` so the kernel can detect an ABI mismatch
+/// instead of silently reading a struct laid out by a different bootloader
+/// version.
+const BOOT_INFO_MAGIC: u64 = 0x_3277_1e92_5c4f_5a3b;
+
+/// Bumped whenever the layout of `BootInfo` or `MemoryMap` changes.
+const BOOT_INFO_VERSION: u16 = 2;
+
+/// An error returned by [`BootInfo::check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootInfoError {
+    /// The magic number at the given address did not match, so the address
+    /// most likely does not point to a `BootInfo` struct at all.
+    InvalidMagic,
+    /// The `BootInfo` at the given address was built by a bootloader with a
+    /// different, incompatible layout.
+    VersionMismatch { expected: u16, found: u16 },
+}
+
+#[repr(C)]
 pub struct BootInfo<'data> {
+    magic: u64,
+    version: u16,
     pub p4_table_addr: u64,
     pub memory_map: MemoryMap,
+    /// The virtual address at which the bootloader mapped all of physical memory.
+    pub physical_memory_offset: u64,
     pub package: &'data [u8],
 }
 
 impl<'data> BootInfo<'data> {
-    pub(crate) fn new(p4_table_addr: u64, memory_map: MemoryMap, package: &'data [u8]) -> Self {
+    pub(crate) fn new(
+        p4_table_addr: u64,
+        memory_map: MemoryMap,
+        physical_memory_offset: u64,
+        package: &'data [u8],
+    ) -> Self {
         BootInfo {
+            magic: BOOT_INFO_MAGIC,
+            version: BOOT_INFO_VERSION,
             p4_table_addr,
             memory_map,
+            physical_memory_offset,
             package,
         }
     }
+
+    /// Validates the magic number and version of the `BootInfo` at `addr`
+    /// before the kernel dereferences it.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must point to memory that is either a valid `BootInfo` or
+    /// readable for the lifetime `'data`; this function only refuses to
+    /// vouch for its *contents*, not for the validity of the pointer itself.
+    pub unsafe fn check(addr: VirtAddr) -> Result<&'data BootInfo<'data>, BootInfoError> {
+        let info = &*(usize_from(addr.as_u64()) as *const BootInfo);
+        if info.magic != BOOT_INFO_MAGIC {
+            return Err(BootInfoError::InvalidMagic);
+        }
+        if info.version != BOOT_INFO_VERSION {
+            return Err(BootInfoError::VersionMismatch {
+                expected: BOOT_INFO_VERSION,
+                found: info.version,
+            });
+        }
+        Ok(info)
+    }
+}
+
+/// A `FrameAllocator` that returns usable frames from a [`BootInfo`]'s [`MemoryMap`].
+///
+/// Wraps a borrowed memory map, walking its `Usable` regions frame by frame
+/// so kernels don't need to hand-roll this on top of `physical_memory_offset`.
+pub struct BootInfoFrameAllocator<'a> {
+    memory_map: &'a MemoryMap,
+    next: usize,
+}
+
+impl<'a> BootInfoFrameAllocator<'a> {
+    /// Creates a `FrameAllocator` from the passed memory map.
+    pub fn init(memory_map: &'a MemoryMap) -> Self {
+        BootInfoFrameAllocator {
+            memory_map,
+            next: 0,
+        }
+    }
+
+    /// Returns an iterator over the usable frames specified in the memory map.
+    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
+        self.memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .flat_map(|r| r.range)
+    }
+}
+
+unsafe impl<'a> FrameAllocator<Size4KiB> for BootInfoFrameAllocator<'a> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.usable_frames().nth(self.next);
+        self.next += 1;
+        frame
+    }
 }
 
+#[repr(C)]
 pub struct MemoryMap {
     entries: [MemoryRegion; 32],
     // u64 instead of usize so that the structure layout is platform
@@ -48,10 +140,15 @@ impl MemoryMap {
         }
     }
 
-    pub fn add_region(&mut self, region: MemoryRegion) {
-        self.entries[self.next_entry_index()] = region;
+    pub fn add_region(&mut self, region: MemoryRegion) -> Result<(), MemoryMapError> {
+        let index = self.next_entry_index();
+        if index >= self.entries.len() {
+            return Err(MemoryMapError::Full);
+        }
+        self.entries[index] = region;
         self.next_entry_index += 1;
         self.sort();
+        Ok(())
     }
 
     pub fn sort(&mut self) {
@@ -85,6 +182,131 @@ impl MemoryMap {
     fn next_entry_index(&self) -> usize {
         self.next_entry_index as usize
     }
+
+    /// Fuses adjacent entries of the same `MemoryRegionType` into one.
+    ///
+    /// Firmware often splits physically contiguous usable RAM into many
+    /// adjacent E820 entries, which can blow past the fixed-size entry
+    /// array. This repeatedly merges any pair of sorted, touching entries
+    /// that share a region type, never merging across differing types.
+    fn merge_contiguous_regions(&mut self) {
+        loop {
+            let len = self.next_entry_index();
+            let merge_at = (0..len.saturating_sub(1)).find(|&i| {
+                let (a, b) = (self.entries[i], self.entries[i + 1]);
+                a.region_type == b.region_type && a.range.end == b.range.start
+            });
+
+            let i = match merge_at {
+                Some(i) => i,
+                None => break,
+            };
+
+            self.entries[i].range = PhysFrame::range(self.entries[i].range.start, self.entries[i + 1].range.end);
+            for j in i + 1..len - 1 {
+                self.entries[j] = self.entries[j + 1];
+            }
+            self.entries[len - 1] = MemoryRegion::empty();
+            self.next_entry_index -= 1;
+        }
+    }
+
+    /// Carves `region` out of the `Usable` region that encloses it, re-tagging
+    /// it with `region.region_type`.
+    ///
+    /// Used by the bootloader to reserve frame ranges for the kernel, page
+    /// tables, stacks, the boot info struct and the package. If `region` lies
+    /// strictly inside an enclosing `Usable` entry, that entry is split into
+    /// up to three entries: a leading usable remainder, the newly-typed
+    /// region, and a trailing usable remainder.
+    pub fn mark_allocated_region(&mut self, region: MemoryRegion) -> Result<(), MemoryMapError> {
+        let len = self.next_entry_index();
+        let index = self.entries[0..len].iter().position(|r| {
+            r.region_type == MemoryRegionType::Usable
+                && region.range.start >= r.range.start
+                && region.range.end <= r.range.end
+        });
+        let index = match index {
+            Some(index) => index,
+            None => return Err(MemoryMapError::RegionNotFound(region)),
+        };
+        let enclosing = self.entries[index];
+
+        let before = MemoryRegion {
+            range: PhysFrame::range(enclosing.range.start, region.range.start),
+            region_type: MemoryRegionType::Usable,
+            gap_shift: MemoryRegion::NO_GAP,
+        };
+        let after = MemoryRegion {
+            range: PhysFrame::range(region.range.end, enclosing.range.end),
+            region_type: MemoryRegionType::Usable,
+            gap_shift: MemoryRegion::NO_GAP,
+        };
+
+        let extra_entries = !before.range.is_empty() as usize + !after.range.is_empty() as usize;
+        if len + extra_entries > self.entries.len() {
+            return Err(MemoryMapError::Full);
+        }
+
+        self.entries[index] = region;
+
+        if !after.range.is_empty() {
+            self.add_region(after)?;
+        }
+        if !before.range.is_empty() {
+            self.add_region(before)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the region that contains `addr`, or `None` if `addr` falls
+    /// into a gap between entries or into a region's periodic gap (see
+    /// `MemoryRegion::gap_shift`).
+    ///
+    /// `region.range.end` is treated as exclusive, matching the rest of the
+    /// memory map.
+    pub fn region_containing(&self, addr: PhysAddr) -> Option<&MemoryRegion> {
+        use core::cmp::Ordering;
+
+        let regions = &self[..];
+        let index = regions
+            .binary_search_by(|r| {
+                if addr < r.range.start.start_address() {
+                    Ordering::Greater
+                } else if addr >= r.range.end.start_address() {
+                    Ordering::Less
+                } else {
+                    Ordering::Equal
+                }
+            })
+            .ok()?;
+
+        let region = &regions[index];
+        if region.is_gap(addr) {
+            None
+        } else {
+            Some(region)
+        }
+    }
+
+    /// Returns whether every address in `[start, start + len)` is backed by
+    /// usable RAM.
+    pub fn is_range_usable(&self, start: PhysAddr, len: u64) -> bool {
+        if len == 0 {
+            return true;
+        }
+
+        let start_frame = PhysFrame::containing_address(start);
+        let last_frame = PhysFrame::containing_address(PhysAddr::new(start.as_u64() + len - 1));
+        let end_frame = last_frame + 1;
+
+        PhysFrame::range(start_frame, end_frame).all(|frame| {
+            self.region_containing(frame.start_address())
+                .map(|region| region.region_type == MemoryRegionType::Usable)
+                .unwrap_or(false)
+        })
+    }
 }
 
 impl Deref for MemoryMap {
@@ -108,13 +330,41 @@ impl fmt::Debug for MemoryMap {
     }
 }
 
+/// An error that can occur while building or mutating a [`MemoryMap`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryMapError {
+    /// The memory map has no free slot for an entry that must be added; it
+    /// holds at most 32 entries.
+    Full,
+    /// A non-usable region overlaps the region that follows it in sorted order.
+    Overlap {
+        a: MemoryRegion,
+        b: MemoryRegion,
+    },
+    /// The firmware reported an E820 region type that is not recognized.
+    InvalidE820Type(u32),
+    /// No usable region fully encloses the region that should be marked allocated.
+    RegionNotFound(MemoryRegion),
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
 pub struct MemoryRegion {
     pub range: PhysFrameRange,
     pub region_type: MemoryRegionType,
+    /// Describes a periodic gap within `range`.
+    ///
+    /// A value of 63 means the region is fully continuous. Any other value
+    /// divides `range` into `1 << gap_shift`-sized blocks and treats every
+    /// other block (addresses with bit `gap_shift` set) as absent, which
+    /// models interleaved/mirrored memory.
+    pub gap_shift: u8,
 }
 
 impl MemoryRegion {
+    /// The `gap_shift` value meaning "no gap; the region is continuous".
+    pub const NO_GAP: u8 = 63;
+
     pub fn empty() -> Self {
         MemoryRegion {
             range: PhysFrame::range(
@@ -122,12 +372,19 @@ impl MemoryRegion {
                 PhysFrame::containing_address(PhysAddr::new(0)),
             ),
             region_type: MemoryRegionType::Empty,
+            gap_shift: MemoryRegion::NO_GAP,
         }
     }
+
+    /// Returns whether `addr` falls into this region's periodic gap, if any.
+    fn is_gap(&self, addr: PhysAddr) -> bool {
+        self.gap_shift != MemoryRegion::NO_GAP && (addr.as_u64() >> self.gap_shift) & 1 == 1
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(dead_code)]
+#[repr(u8)]
 pub enum MemoryRegionType {
     /// free RAM
     Usable,
@@ -170,34 +427,42 @@ struct E820MemoryRegion {
     pub acpi_extended_attributes: u32,
 }
 
-impl From<E820MemoryRegion> for MemoryRegion {
-    fn from(region: E820MemoryRegion) -> MemoryRegion {
+impl core::convert::TryFrom<E820MemoryRegion> for MemoryRegion {
+    type Error = MemoryMapError;
+
+    fn try_from(region: E820MemoryRegion) -> Result<MemoryRegion, MemoryMapError> {
         let region_type = match region.region_type {
             1 => MemoryRegionType::Usable,
             2 => MemoryRegionType::Reserved,
             3 => MemoryRegionType::AcpiReclaimable,
             4 => MemoryRegionType::AcpiNvs,
             5 => MemoryRegionType::BadMemory,
-            t => panic!("invalid region type {}", t),
+            t => return Err(MemoryMapError::InvalidE820Type(t)),
         };
-        MemoryRegion {
+        Ok(MemoryRegion {
             range: PhysFrame::range(
                 PhysFrame::containing_address(PhysAddr::new(region.start_addr)),
                 PhysFrame::containing_address(PhysAddr::new(region.start_addr + region.len)),
             ),
             region_type,
-        }
+            gap_shift: MemoryRegion::NO_GAP,
+        })
     }
 }
 
-pub(crate) fn create_from(memory_map_addr: VirtAddr, entry_count: u64) -> MemoryMap {
+pub(crate) fn create_from(
+    memory_map_addr: VirtAddr,
+    entry_count: u64,
+) -> Result<MemoryMap, MemoryMapError> {
+    use core::convert::TryFrom;
+
     let memory_map_start_ptr = usize_from(memory_map_addr.as_u64()) as *const E820MemoryRegion;
     let e820_memory_map =
         unsafe { slice::from_raw_parts(memory_map_start_ptr, usize_from(entry_count)) };
 
     let mut memory_map = MemoryMap::new();
     for region in e820_memory_map {
-        memory_map.add_region(MemoryRegion::from(*region));
+        memory_map.add_region(MemoryRegion::try_from(*region)?)?;
     }
 
     memory_map.sort();
@@ -209,11 +474,130 @@ pub(crate) fn create_from(memory_map_addr: VirtAddr, entry_count: u64) -> Memory
                 if region.region_type == MemoryRegionType::Usable {
                     region.range.end = next.range.start;
                 } else {
-                    panic!("two non-usable regions overlap: {:?} {:?}", region, next);
+                    return Err(MemoryMapError::Overlap {
+                        a: *region,
+                        b: **next,
+                    });
                 }
             }
         }
     }
 
-    memory_map
+    memory_map.merge_contiguous_regions();
+
+    Ok(memory_map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(start: u64, end: u64, region_type: MemoryRegionType) -> MemoryRegion {
+        MemoryRegion {
+            range: PhysFrame::range(
+                PhysFrame::containing_address(PhysAddr::new(start)),
+                PhysFrame::containing_address(PhysAddr::new(end)),
+            ),
+            region_type,
+            gap_shift: MemoryRegion::NO_GAP,
+        }
+    }
+
+    #[test]
+    fn merge_contiguous_regions_fuses_touching_same_type_entries() {
+        let mut map = MemoryMap::new();
+        map.add_region(region(0x0000, 0x1000, MemoryRegionType::Usable))
+            .unwrap();
+        map.add_region(region(0x1000, 0x2000, MemoryRegionType::Usable))
+            .unwrap();
+        map.add_region(region(0x2000, 0x3000, MemoryRegionType::Reserved))
+            .unwrap();
+        map.add_region(region(0x3000, 0x4000, MemoryRegionType::Usable))
+            .unwrap();
+
+        map.merge_contiguous_regions();
+
+        assert_eq!(map.len(), 3);
+        assert_eq!(map[0].region_type, MemoryRegionType::Usable);
+        assert_eq!(
+            map[0].range,
+            PhysFrame::range(
+                PhysFrame::containing_address(PhysAddr::new(0x0000)),
+                PhysFrame::containing_address(PhysAddr::new(0x2000)),
+            )
+        );
+        assert_eq!(map[1].region_type, MemoryRegionType::Reserved);
+        assert_eq!(map[2].region_type, MemoryRegionType::Usable);
+    }
+
+    #[test]
+    fn mark_allocated_region_splits_into_before_middle_after() {
+        let mut map = MemoryMap::new();
+        map.add_region(region(0x0000, 0x5000, MemoryRegionType::Usable))
+            .unwrap();
+
+        map.mark_allocated_region(region(0x2000, 0x3000, MemoryRegionType::Kernel))
+            .unwrap();
+
+        assert_eq!(map.len(), 3);
+        assert_eq!(map[0].region_type, MemoryRegionType::Usable);
+        assert_eq!(
+            map[0].range.end,
+            PhysFrame::containing_address(PhysAddr::new(0x2000))
+        );
+        assert_eq!(map[1].region_type, MemoryRegionType::Kernel);
+        assert_eq!(map[2].region_type, MemoryRegionType::Usable);
+        assert_eq!(
+            map[2].range.start,
+            PhysFrame::containing_address(PhysAddr::new(0x3000))
+        );
+    }
+
+    #[test]
+    fn mark_allocated_region_errors_when_no_enclosing_usable_region() {
+        let mut map = MemoryMap::new();
+        map.add_region(region(0x0000, 0x1000, MemoryRegionType::Reserved))
+            .unwrap();
+
+        let request = region(0x0000, 0x1000, MemoryRegionType::Kernel);
+        assert_eq!(
+            map.mark_allocated_region(request),
+            Err(MemoryMapError::RegionNotFound(request))
+        );
+    }
+
+    #[test]
+    fn region_containing_finds_owning_region_and_respects_gaps() {
+        let mut map = MemoryMap::new();
+        map.add_region(region(0x0000, 0x1000, MemoryRegionType::Usable))
+            .unwrap();
+        map.add_region(region(0x2000, 0x3000, MemoryRegionType::Reserved))
+            .unwrap();
+
+        assert_eq!(
+            map.region_containing(PhysAddr::new(0x0500))
+                .map(|r| r.region_type),
+            Some(MemoryRegionType::Usable)
+        );
+        assert!(map.region_containing(PhysAddr::new(0x1500)).is_none());
+
+        let mut gapped = region(0x4000, 0x6000, MemoryRegionType::Usable);
+        gapped.gap_shift = 12;
+        map.add_region(gapped).unwrap();
+
+        assert!(map.region_containing(PhysAddr::new(0x4000)).is_some());
+        assert!(map.region_containing(PhysAddr::new(0x5000)).is_none());
+    }
+
+    #[test]
+    fn is_range_usable_detects_spill_into_the_next_region() {
+        let mut map = MemoryMap::new();
+        map.add_region(region(0x2000, 0x3000, MemoryRegionType::Usable))
+            .unwrap();
+        map.add_region(region(0x3000, 0x4000, MemoryRegionType::Reserved))
+            .unwrap();
+
+        assert!(map.is_range_usable(PhysAddr::new(0x2000), 0x1000));
+        assert!(!map.is_range_usable(PhysAddr::new(0x2800), 0x1000));
+    }
 }